@@ -1,6 +1,7 @@
 use anyhow::Context;
+use memmap2::{Mmap, MmapOptions};
 use std::fs;
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
 // <https://stackoverflow.com/questions/67087597/is-it-possible-to-use-rusts-log-info-for-tests>
@@ -36,51 +37,103 @@ fn app_data_dir_from_libapp_path(libapp_path: &str) -> Result<PathBuf, InitError
 /// Android splits APKs into multiple files, and we need to find the one that
 /// contains the library we want.  However the architecture names for the
 /// apk splits is different from the architecture names for the library paths
-/// within those split apks.  We need to know both.
+/// within those split apks.  We need to know both, plus the ELF `e_machine`
+/// a library under this ABI is expected to declare, since a fallback ABI
+/// (e.g. armeabi-v7a on an aarch64 device) is a genuinely different machine
+/// than the device's own.
 struct ArchNames {
     // Name used in the apk split, e.g. base-armeabi_v7a.apk
     apk_split: &'static str,
     // Name used in the library path, e.g. lib/armeabi-v7a/libapp.so
     // Note the - instead of _.
     lib_dir: &'static str,
+    // ELF e_machine value a valid library under lib_dir must declare.
+    // https://refspecs.linuxbase.org/elf/gabi4+/ch4.eheader.html
+    machine: u16,
 }
 
-/// Get the APK split names for the current architecture.
-fn android_arch_names() -> &'static ArchNames {
+// ELF e_machine values for the architectures we ship.
+const EM_386: u16 = 3;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+/// Get the APK split names acceptable for the current architecture, in
+/// priority order (the device's actual architecture first, followed by any
+/// runtime-compatible fallback ABIs). Many apps ship a single
+/// compatible-but-secondary ABI (e.g. an armeabi-v7a-only build on an
+/// aarch64 device, or an x86-only build on an x86_64 device), so it's worth
+/// trying those before giving up and falling back to base.apk. Only
+/// same-family fallbacks are listed: armeabi-v7a is not tried on x86 or
+/// x86_64 devices.
+fn android_arch_names() -> &'static [ArchNames] {
     // This was generated by looking at what apk splits are generated by
     // bundletool.
     // https://developer.android.com/ndk/guides/abis
     #[cfg(target_arch = "x86")]
-    static ARCH: ArchNames = ArchNames {
+    static ARCHES: &[ArchNames] = &[ArchNames {
         apk_split: "x86",
         lib_dir: "x86",
-    };
+        machine: EM_386,
+    }];
     #[cfg(target_arch = "x86_64")]
     // x86_64 uses _ for both split and library paths.
-    static ARCH: ArchNames = ArchNames {
-        apk_split: "x86_64", // e.g. standalone-x86_64_hdpi.apk
-        lib_dir: "x86_64",   // e.g. lib/x86_64/libapp.so
-    };
+    static ARCHES: &[ArchNames] = &[
+        ArchNames {
+            apk_split: "x86_64", // e.g. standalone-x86_64_hdpi.apk
+            lib_dir: "x86_64",   // e.g. lib/x86_64/libapp.so
+            machine: EM_X86_64,
+        },
+        // x86 emulator images are sometimes shipped without an x86_64 split.
+        ArchNames {
+            apk_split: "x86",
+            lib_dir: "x86",
+            machine: EM_386,
+        },
+    ];
     #[cfg(target_arch = "aarch64")]
     // Note the _ in the split name, but the - in the lib dir.
-    static ARCH: ArchNames = ArchNames {
-        apk_split: "arm64_v8a",
-        lib_dir: "arm64-v8a",
-    };
+    static ARCHES: &[ArchNames] = &[
+        ArchNames {
+            apk_split: "arm64_v8a",
+            lib_dir: "arm64-v8a",
+            machine: EM_AARCH64,
+        },
+        // 64-bit devices can run 32-bit armeabi-v7a libraries, which is all
+        // some apps ship.
+        ArchNames {
+            apk_split: "armeabi_v7a",
+            lib_dir: "armeabi-v7a",
+            machine: EM_ARM,
+        },
+    ];
     #[cfg(target_arch = "arm")]
     // Note the _ in the split name, but the - in the lib dir.
-    static ARCH: ArchNames = ArchNames {
+    static ARCHES: &[ArchNames] = &[ArchNames {
         apk_split: "armeabi_v7a", // e.g. base-armeabi_v7a.apk
         lib_dir: "armeabi-v7a",   // e.g. lib/armeabi-v7a/libapp.so
-    };
-    &ARCH
+        machine: EM_ARM,
+    }];
+    ARCHES
+}
+
+fn relative_lib_path_for(arch: &ArchNames, lib_name: &str) -> PathBuf {
+    PathBuf::from("lib").join(arch.lib_dir).join(lib_name)
 }
 
 // This is public so c_api can use this for testing.
 pub(crate) fn get_relative_lib_path(lib_name: &str) -> PathBuf {
-    PathBuf::from("lib")
-        .join(android_arch_names().lib_dir)
-        .join(lib_name)
+    relative_lib_path_for(&android_arch_names()[0], lib_name)
+}
+
+/// All (relative lib path, expected ELF e_machine) pairs worth trying, in
+/// priority order (primary ABI first), for devices that only shipped a
+/// runtime-compatible secondary ABI.
+fn lib_lookup_candidates(lib_name: &str) -> Vec<(PathBuf, u16)> {
+    android_arch_names()
+        .iter()
+        .map(|arch| (relative_lib_path_for(arch, lib_name), arch.machine))
+        .collect()
 }
 
 // This is just a tuple of the archive and the internal path to the library.
@@ -91,72 +144,281 @@ pub(crate) fn get_relative_lib_path(lib_name: &str) -> PathBuf {
 struct ZipLocation {
     archive: zip::ZipArchive<fs::File>,
     internal_path: String,
+    // The apk this archive was opened from, kept around so open_base_lib can
+    // re-open the file for mmap-ing without threading the path separately.
+    path: PathBuf,
+}
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ET_DYN: u16 = 3;
+
+/// Check that `header` (the first 20 bytes of a candidate library entry) is
+/// a valid ELF shared object for `expected_machine`. A stale or
+/// partially-written split APK could have a matching file name but the
+/// wrong (or no) contents; this turns that into a clean "keep looking"
+/// rather than committing to a corrupt or cross-arch library.
+/// `expected_machine` is the ABI directory's own e_machine (see
+/// `ArchNames::machine`), not necessarily the device's native machine, since
+/// a valid fallback-ABI library (e.g. armeabi-v7a on an aarch64 device) is a
+/// different machine than the device itself.
+fn is_valid_lib_header(header: &[u8], expected_machine: u16) -> bool {
+    if header.len() < 20 || header[0..4] != ELF_MAGIC {
+        return false;
+    }
+    let e_type = u16::from_le_bytes([header[16], header[17]]);
+    let e_machine = u16::from_le_bytes([header[18], header[19]]);
+    e_type == ET_DYN && e_machine == expected_machine
 }
 
-/// Given a zip file, check if it contains the library we want.
-fn check_for_lib_path(zip_path: &Path, lib_path: &str) -> anyhow::Result<ZipLocation> {
-    let apk = zip::ZipArchive::new(fs::File::open(zip_path)?)?;
+/// Given a zip file, check if it contains the library we want, and that the
+/// library is actually a valid ELF shared object for `expected_machine`.
+fn check_for_lib_path(
+    zip_path: &Path,
+    lib_path: &str,
+    expected_machine: u16,
+) -> anyhow::Result<ZipLocation> {
+    let mut apk = zip::ZipArchive::new(fs::File::open(zip_path)?)?;
     if apk.file_names().any(|name| name == lib_path) {
+        let mut header = [0u8; 20];
+        let read_ok = {
+            let mut entry = apk.by_name(lib_path)?;
+            entry.read_exact(&mut header).is_ok()
+        };
+        if !read_ok || !is_valid_lib_header(&header, expected_machine) {
+            return Err(anyhow::anyhow!(
+                "Library entry failed ELF validation: {}",
+                lib_path
+            ));
+        }
         return Ok(ZipLocation {
             archive: apk,
             internal_path: lib_path.to_owned(),
+            path: zip_path.to_owned(),
         });
     }
     Err(anyhow::anyhow!("Library not found in APK"))
 }
 
+/// The OS page size, used to check whether a zip entry's data is aligned
+/// well enough to `mmap` directly rather than copying it out.
+fn page_size() -> u64 {
+    // SAFETY: sysconf with _SC_PAGESIZE is always safe to call and never
+    // fails on a POSIX system such as Android.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+/// Either a memory-mapped view of a `Stored` (uncompressed) library entry
+/// living directly inside the APK, or a buffered copy read out of a
+/// `Deflated` (or otherwise non-mappable) entry. Both variants implement
+/// `Read + Seek`, which is all `bipatch` needs, so callers don't need to
+/// know which path was taken.
+pub(crate) enum LibSource {
+    Mapped(Cursor<Mmap>),
+    Buffered(Cursor<Vec<u8>>),
+}
+
+impl Read for LibSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            LibSource::Mapped(cursor) => cursor.read(buf),
+            LibSource::Buffered(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Seek for LibSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            LibSource::Mapped(cursor) => cursor.seek(pos),
+            LibSource::Buffered(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+/// Try to mmap `len` bytes of `apk_path` starting at `data_start` as a
+/// zero-copy `LibSource`. Returns `Ok(None)` (rather than mapping) when
+/// `data_start` isn't page-aligned, or when the mapped bytes don't actually
+/// start with an ELF header, so callers can fall back to the buffered path
+/// instead of handing a wrong or garbage region to `bipatch`.
+fn try_mmap_lib(apk_path: &Path, data_start: u64, len: usize) -> anyhow::Result<Option<LibSource>> {
+    if data_start % page_size() != 0 {
+        return Ok(None);
+    }
+    let apk_file = fs::File::open(apk_path)?;
+    // SAFETY: we assume the APK on disk isn't mutated out from under us
+    // while mapped, the same assumption bipatch already makes when reading
+    // libapp.so from disk.
+    let mmap = unsafe { MmapOptions::new().offset(data_start).len(len).map(&apk_file)? };
+    if mmap.len() < ELF_MAGIC.len() || mmap[..ELF_MAGIC.len()] != ELF_MAGIC {
+        return Ok(None);
+    }
+    Ok(Some(LibSource::Mapped(Cursor::new(mmap))))
+}
+
+/// Parse the contents of `/proc/self/maps` (`maps`) looking for the
+/// executable mapping backing `lib_name`, returning the APK it was mapped
+/// from along with the mapping's file offset. Split out from
+/// `libapp_location_from_proc_maps` so tests can feed it synthetic maps
+/// content instead of the real `/proc/self/maps`.
+///
+/// When `libapp.so` is mapped directly out of an uncompressed, page-aligned
+/// APK entry, the dynamic linker's mapping records the true source file, so
+/// we don't need to guess at it the way `app_data_dir_from_libapp_path`
+/// does. Entries look like:
+///   7f1234000-7f1235000 r-xp 00012000 fd:03 123  /data/app/.../base.apk
+/// with the file offset in the third column. Newer Android appends
+/// `!/lib/<abi>/<lib_name>` to the apk's pathname; older devices just show
+/// the apk itself, in which case we confirm the apk actually contains the
+/// library before trusting the mapping.
+fn libapp_location_from_maps_str(maps: &str, lib_name: &str) -> Result<(PathBuf, u64), InitError> {
+    let candidates = lib_lookup_candidates(lib_name);
+
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let _range = fields.next();
+        let perms = fields.next();
+        if perms != Some("r-xp") {
+            continue;
+        }
+        let offset = match fields.next().and_then(|h| u64::from_str_radix(h, 16).ok()) {
+            Some(offset) => offset,
+            None => continue,
+        };
+        let _dev = fields.next();
+        let _inode = fields.next();
+        let pathname = match fields.next() {
+            Some(pathname) => pathname,
+            None => continue,
+        };
+
+        if let Some(bang) = pathname.find('!') {
+            let apk_path = &pathname[..bang];
+            let embedded = pathname[bang + 1..].trim_start_matches('/');
+            if candidates
+                .iter()
+                .any(|(suffix, _machine)| Path::new(embedded) == suffix.as_path())
+            {
+                return Ok((PathBuf::from(apk_path), offset));
+            }
+        } else if pathname.ends_with(".apk") {
+            let found = candidates.iter().any(|(suffix, machine)| {
+                suffix
+                    .to_str()
+                    .map(|lib_path| {
+                        check_for_lib_path(Path::new(pathname), lib_path, *machine).is_ok()
+                    })
+                    .unwrap_or(false)
+            });
+            if found {
+                return Ok((PathBuf::from(pathname), offset));
+            }
+        }
+    }
+
+    Err(InitError::InvalidArgument(
+        "lib_name".to_string(),
+        format!("{} not found in /proc/self/maps", lib_name),
+    ))
+}
+
+/// Scan `/proc/self/maps` for the executable mapping backing `lib_name` and
+/// return the APK it was mapped from along with the mapping's file offset.
+/// See `libapp_location_from_maps_str` for the parsing details.
+pub(crate) fn libapp_location_from_proc_maps(lib_name: &str) -> Result<(PathBuf, u64), InitError> {
+    let maps = fs::read_to_string("/proc/self/maps").map_err(|e| {
+        InitError::InvalidArgument("/proc/self/maps".to_string(), e.to_string())
+    })?;
+    libapp_location_from_maps_str(&maps, lib_name)
+}
+
 /// Given a directory of APKs, find the one that contains the library we want.
-/// This has to be done due to split APKs.
+/// This has to be done due to split APKs. ABIs are tried in the priority
+/// order returned by `android_arch_names`, so a device whose preferred split
+/// is missing (but which shipped a runtime-compatible secondary ABI) still
+/// finds a usable library before giving up and falling back to base.apk.
 fn find_and_open_lib(apks_dir: &Path, lib_name: &str) -> anyhow::Result<ZipLocation> {
     // Read the library out of the APK.  We only really need to do this if it
     // isn't already extracted on disk (which it won't be by default from the
     // play store).
 
-    // First check ones with our arch in the name, in any order.
-    let arch = android_arch_names();
-    let lib_path = get_relative_lib_path(lib_name)
-        .to_str()
-        .context("Invalid lib path")?
-        .to_owned();
-
-    for entry in fs::read_dir(apks_dir)? {
-        let entry = entry?;
-        let path = entry.path(); // returns the absolute path.
-        if path.is_dir() {
-            continue;
+    // Prefer the exact APK the dynamic linker tells us it mapped libapp.so
+    // from; this sidesteps the directory scan (and its split-matching
+    // heuristics) entirely when it's available. We only use this to locate
+    // the APK *file*: the r-xp mapping's file offset is the .text segment's
+    // offset, not the library's base offset inside the APK (there's an r--p
+    // segment for the ELF header/rodata before it), so it isn't usable as
+    // data_start. open_base_lib re-derives that from the zip entry itself.
+    if let Ok((apk_path, _offset)) = libapp_location_from_proc_maps(lib_name) {
+        for arch in android_arch_names() {
+            let lib_path = relative_lib_path_for(arch, lib_name);
+            if let Some(lib_path) = lib_path.to_str() {
+                if let Ok(zip) = check_for_lib_path(&apk_path, lib_path, arch.machine) {
+                    debug!("Found lib via /proc/self/maps: {:?}", apk_path);
+                    return Ok(zip);
+                }
+            }
         }
-        // file_name returns an OsStr which only ever fails to convert to a str
-        // on systems that support non-unicode filenames, which is not a problem
-        // for us on Android, but we still take extra caution to never crash.
-        // This is written as a nested if statement to avoid a double unwrap
-        // as well as let coverage see us take all paths (since we'd never
-        // take an OsStr fail path).
-        if let Some(filename) = path.file_name() {
-            if let Some(filename) = filename.to_str() {
-                // Note this only examines .apks with the arch in the name
-                // so it will not examine the base.apk.
-                // We could remove the apk_split check and assume that the
-                // first apk to contain the library is the right one?
-                if filename.ends_with(".apk") && filename.contains(arch.apk_split) {
-                    debug!("Checking APK: {:?}", path);
-                    if let Ok(zip) = check_for_lib_path(&path, &lib_path) {
-                        debug!("Found lib in apk split: {:?}", path);
-                        return Ok(zip);
+    }
+
+    for arch in android_arch_names() {
+        let lib_path = relative_lib_path_for(arch, lib_name)
+            .to_str()
+            .context("Invalid lib path")?
+            .to_owned();
+
+        // First check ones with our arch in the name, in any order.
+        for entry in fs::read_dir(apks_dir)? {
+            let entry = entry?;
+            let path = entry.path(); // returns the absolute path.
+            if path.is_dir() {
+                continue;
+            }
+            // file_name returns an OsStr which only ever fails to convert to a str
+            // on systems that support non-unicode filenames, which is not a problem
+            // for us on Android, but we still take extra caution to never crash.
+            // This is written as a nested if statement to avoid a double unwrap
+            // as well as let coverage see us take all paths (since we'd never
+            // take an OsStr fail path).
+            if let Some(filename) = path.file_name() {
+                if let Some(filename) = filename.to_str() {
+                    // Note this only examines .apks with the arch in the name
+                    // so it will not examine the base.apk.
+                    // We could remove the apk_split check and assume that the
+                    // first apk to contain the library is the right one?
+                    if filename.ends_with(".apk") && filename.contains(arch.apk_split) {
+                        debug!("Checking APK: {:?}", path);
+                        if let Ok(zip) = check_for_lib_path(&path, &lib_path, arch.machine) {
+                            debug!("Found lib in apk split: {:?}", path);
+                            return Ok(zip);
+                        }
                     }
                 }
             }
         }
     }
-    // If we failed to find a split, assume the base.apk contains the library.
+
+    // If we failed to find a split for any acceptable ABI, assume the
+    // base.apk contains the library, again trying each ABI in priority order.
     let base_apk_path = apks_dir.join("base.apk");
-    debug!("Checking base APK: {:?}", base_apk_path);
-    check_for_lib_path(&base_apk_path, &lib_path)
+    let mut result = Err(anyhow::anyhow!("Library not found in APK"));
+    for arch in android_arch_names() {
+        let lib_path = relative_lib_path_for(arch, lib_name)
+            .to_str()
+            .context("Invalid lib path")?
+            .to_owned();
+        debug!("Checking base APK: {:?} ({})", base_apk_path, lib_path);
+        result = check_for_lib_path(&base_apk_path, &lib_path, arch.machine);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
 }
 
 /// Given a directory of APKs, find the one that contains the library we want.
 /// This has to be done due to split APKs.
 /// This is public so c_api can use this for testing.
-pub(crate) fn open_base_lib(apks_dir: &Path, lib_name: &str) -> anyhow::Result<Cursor<Vec<u8>>> {
+pub(crate) fn open_base_lib(apks_dir: &Path, lib_name: &str) -> anyhow::Result<LibSource> {
     // As far as I can tell, Android provides no apis for reading per-platform
     // assets (e.g. libapp.so) from an APK.  Both Facebook and Chromium
     // seem to have written their own code to do this:
@@ -175,15 +437,33 @@ pub(crate) fn open_base_lib(apks_dir: &Path, lib_name: &str) -> anyhow::Result<C
         .by_name(&zip_location.internal_path)
         .context("Failed to find libapp.so in APK")?;
 
+    // Modern Android builds (android:extractNativeLibs="false") store
+    // libapp.so uncompressed and page-aligned inside the APK so the linker
+    // can map it directly. When that's the case here too, mmap the same
+    // byte range instead of copying tens of megabytes into a Vec.
+    if zip_file.compression() == zip::CompressionMethod::Stored {
+        let data_start = zip_file.data_start();
+        if let Some(lib_source) =
+            try_mmap_lib(&zip_location.path, data_start, zip_file.size() as usize)?
+        {
+            return Ok(lib_source);
+        }
+    }
+
     // Cursor (rather than ZipFile) is only necessary because bipatch expects
     // Seek + Read for the input file.  I don't think it actually needs to
-    // seek backwards, so Read is probably sufficient.  If we made bipatch
-    // only depend on Read we could avoid loading the library fully into memory.
+    // seek backwards, so Read is probably sufficient.  Deflated entries
+    // (and mis-aligned Stored ones) can't be mapped, so fall back to reading
+    // the whole entry into memory.
     let mut buffer = Vec::new();
     zip_file.read_to_end(&mut buffer)?;
-    Ok(Cursor::new(buffer))
+    Ok(LibSource::Buffered(Cursor::new(buffer)))
 }
 
+// This is still used as the fallback apks_dir for find_and_open_lib's
+// directory scan. libapp_location_from_proc_maps is tried first and gives
+// us the exact apk directly, without this path heuristic (the mapping's
+// offset it also returns is not used).
 pub fn libapp_path_from_settings(original_libapp_paths: &[String]) -> Result<PathBuf, InitError> {
     // FIXME: This makes the assumption that the last path provided is the full
     // path to the libapp.so file.  This is true for the current engine, but
@@ -213,22 +493,47 @@ pub fn libapp_path_from_settings(original_libapp_paths: &[String]) -> Result<Pat
 #[cfg(test)]
 mod tests {
     use std::fs::File;
+    use std::io::{Read, Write};
     use std::path::Path;
     use tempdir::TempDir;
     use zip::write::FileOptions;
     use zip::ZipWriter;
 
-    // Takes a path to the zip to create as well as a list of file names to
-    // create in the zip.  The files will be empty.
-    fn create_zip_with_empty_files(zip_path: &Path, files: Vec<&str>) {
+    // Takes a path to the zip to create as well as a list of (name, contents)
+    // pairs to write into it.
+    fn create_zip_with_files(zip_path: &Path, files: Vec<(&str, &[u8])>) {
         let file = File::create(zip_path).unwrap();
         let mut zip = ZipWriter::new(file);
-        for file in files {
-            zip.start_file(file, FileOptions::default()).unwrap();
+        for (name, contents) in files {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
         }
         zip.finish().unwrap();
     }
 
+    // Takes a path to the zip to create as well as a list of file names to
+    // create in the zip.  The files will be empty, so they'll fail ELF
+    // validation if opened as a library entry.
+    fn create_zip_with_empty_files(zip_path: &Path, files: Vec<&str>) {
+        create_zip_with_files(
+            zip_path,
+            files.into_iter().map(|name| (name, &b""[..])).collect(),
+        );
+    }
+
+    // A minimal, valid ELF shared-object header declaring `machine`, so
+    // entries that are supposed to be found pass ELF validation. Callers
+    // pass the e_machine of whichever ABI the entry is supposed to be
+    // found under (not necessarily the device's own), since a valid
+    // fallback-ABI library is a different machine than the primary one.
+    fn fake_lib_bytes(machine: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 20];
+        header[0..4].copy_from_slice(&super::ELF_MAGIC);
+        header[16..18].copy_from_slice(&super::ET_DYN.to_le_bytes());
+        header[18..20].copy_from_slice(&machine.to_le_bytes());
+        header
+    }
+
     #[test]
     fn find_and_open_lib_test() {
         let tmp_dir = TempDir::new("example").unwrap();
@@ -251,13 +556,13 @@ mod tests {
 
     #[test]
     fn find_and_open_lib_base_apk() {
-        // Create a valid apk (zip) with an empty libapp.so with the right path.
+        // Create a valid apk (zip) with a valid libapp.so at the right path.
         let tmp_dir = TempDir::new("example").unwrap();
 
         let base_apk_path = tmp_dir.path().join("base.apk");
-        let arch = super::android_arch_names();
+        let arch = &super::android_arch_names()[0];
         let lib_path = format!("lib/{}/libapp.so", arch.lib_dir);
-        create_zip_with_empty_files(&base_apk_path, vec![&lib_path]);
+        create_zip_with_files(&base_apk_path, vec![(&lib_path, &fake_lib_bytes(arch.machine))]);
 
         let zip_location = super::find_and_open_lib(tmp_dir.path(), "libapp.so").unwrap();
         // Success!
@@ -270,7 +575,7 @@ mod tests {
 
     #[test]
     fn find_and_open_lib_split_apk() {
-        // Create a valid apk (zip) with an empty libapp.so with the right path
+        // Create a valid apk (zip) with a valid libapp.so at the right path
         // and a base apk with the wrong path.
         let tmp_dir = TempDir::new("example").unwrap();
 
@@ -279,16 +584,16 @@ mod tests {
         create_zip_with_empty_files(&base_apk_path, vec!["lib/wrong/libapp.so"]);
 
         // Write a split apk with the right arch.
-        let arch = super::android_arch_names();
+        let arch = &super::android_arch_names()[0];
         let split_apk_name = format!("app-hdpi{}-release.apk", arch.apk_split);
         let split_apk_path: std::path::PathBuf = tmp_dir.path().join(split_apk_name);
         let lib_path = format!("lib/{}/libapp.so", arch.lib_dir);
-        create_zip_with_empty_files(&split_apk_path, vec![&lib_path]);
+        create_zip_with_files(&split_apk_path, vec![(&lib_path, &fake_lib_bytes(arch.machine))]);
 
         // Write another apk early in the alphabet we skip over since it isn't
         // a split apk.
         let split_apk_path: std::path::PathBuf = tmp_dir.path().join("aaa.apk");
-        create_zip_with_empty_files(&split_apk_path, vec![&lib_path]);
+        create_zip_with_files(&split_apk_path, vec![(&lib_path, &fake_lib_bytes(arch.machine))]);
 
         // Write an apk with our arch name but not our library.
         let split_apk_name = format!("aaa{}.apk", arch.apk_split);
@@ -300,6 +605,56 @@ mod tests {
         assert_eq!(zip_location.internal_path, lib_path);
     }
 
+    #[test]
+    fn find_and_open_lib_skips_corrupt_split() {
+        // A split apk that matches by name but whose "library" is garbage
+        // (e.g. a partially-written file) should be skipped rather than
+        // accepted, falling through to the base apk's valid copy.
+        let tmp_dir = TempDir::new("example").unwrap();
+
+        let arch = &super::android_arch_names()[0];
+        let lib_path = format!("lib/{}/libapp.so", arch.lib_dir);
+
+        let split_apk_name = format!("app-hdpi{}-release.apk", arch.apk_split);
+        let split_apk_path = tmp_dir.path().join(split_apk_name);
+        create_zip_with_empty_files(&split_apk_path, vec![&lib_path]);
+
+        let base_apk_path = tmp_dir.path().join("base.apk");
+        create_zip_with_files(&base_apk_path, vec![(&lib_path, &fake_lib_bytes(arch.machine))]);
+
+        let zip_location = super::find_and_open_lib(tmp_dir.path(), "libapp.so").unwrap();
+        assert_eq!(zip_location.internal_path, lib_path);
+        assert_eq!(zip_location.path, base_apk_path);
+    }
+
+    #[test]
+    fn find_and_open_lib_abi_fallback() {
+        // If the device's primary ABI split wasn't delivered, we should
+        // still find a library in a runtime-compatible fallback ABI.
+        let arches = super::android_arch_names();
+        if arches.len() < 2 {
+            // This test target only accepts a single ABI, so there's no
+            // fallback to exercise.
+            return;
+        }
+        let fallback = &arches[1];
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let lib_path = format!("lib/{}/libapp.so", fallback.lib_dir);
+        let split_apk_name = format!("app-hdpi{}-release.apk", fallback.apk_split);
+        let split_apk_path = tmp_dir.path().join(split_apk_name);
+        // Give the fallback entry the fallback ABI's own e_machine (not the
+        // primary ABI's), since that's what a real cross-ABI library would
+        // declare; this is the whole case the fallback logic has to handle.
+        create_zip_with_files(
+            &split_apk_path,
+            vec![(&lib_path, &fake_lib_bytes(fallback.machine))],
+        );
+
+        let zip_location = super::find_and_open_lib(tmp_dir.path(), "libapp.so").unwrap();
+        assert_eq!(zip_location.internal_path, lib_path);
+    }
+
     #[test]
     fn app_data_dir_from_libapp_path_test() {
         let path = "/data/app/~~7LtReIkm5snW_oXeDoJ5TQ==/com.example.shorebird_test-rpkDZSLBRv2jWcc1gQpwdg==/lib/x86_64/libapp.so";
@@ -310,10 +665,113 @@ mod tests {
         );
     }
 
+    #[test]
+    fn libapp_location_from_maps_str_test() {
+        // Newer Android: the apk path has the internal library path appended
+        // after a `!`, so no filesystem access is needed to trust it.
+        let maps = format!(
+            "7f1234000-7f1235000 r-xp 00012000 fd:03 123  /data/app/base.apk!/lib/{}/libapp.so\n\
+             7f1236000-7f1237000 r--p 00000000 fd:03 123  /data/app/base.apk\n",
+            super::android_arch_names()[0].lib_dir
+        );
+        let (apk_path, offset) = super::libapp_location_from_maps_str(&maps, "libapp.so").unwrap();
+        assert_eq!(apk_path, std::path::PathBuf::from("/data/app/base.apk"));
+        assert_eq!(offset, 0x12000);
+
+        // Older Android: just the bare apk path, so we confirm the library
+        // is actually in it before trusting the mapping's offset.
+        let tmp_dir = TempDir::new("example").unwrap();
+        let arch = &super::android_arch_names()[0];
+        let lib_path = format!("lib/{}/libapp.so", arch.lib_dir);
+        let apk_path = tmp_dir.path().join("base.apk");
+        create_zip_with_files(&apk_path, vec![(&lib_path, &fake_lib_bytes(arch.machine))]);
+
+        let maps = format!(
+            "7f1234000-7f1235000 r-xp 00045000 fd:03 123  {}\n",
+            apk_path.display()
+        );
+        let (found_path, offset) =
+            super::libapp_location_from_maps_str(&maps, "libapp.so").unwrap();
+        assert_eq!(found_path, apk_path);
+        assert_eq!(offset, 0x45000);
+
+        // An r-xp mapping of something that isn't a matching apk is ignored.
+        let error =
+            super::libapp_location_from_maps_str("7f1234000-7f1235000 r-xp 0 fd:03 123  /dev/null", "libapp.so")
+                .unwrap_err();
+        assert!(error.to_string().contains("not found in /proc/self/maps"));
+    }
+
     #[test]
     fn open_base_lib_test() {
         let tmp_dir = TempDir::new("example").unwrap();
         let error = super::open_base_lib(tmp_dir.path(), "libapp.so").unwrap_err();
         assert!(error.to_string().contains("No such file or directory"));
     }
+
+    #[test]
+    fn open_base_lib_mmaps_stored_page_aligned_entry() {
+        // A Stored, page-aligned libapp.so should be opened via mmap rather
+        // than copied, so we get the zero-copy path this request is for.
+        let tmp_dir = TempDir::new("example").unwrap();
+        let base_apk_path = tmp_dir.path().join("base.apk");
+        let arch = &super::android_arch_names()[0];
+        let lib_path = format!("lib/{}/libapp.so", arch.lib_dir);
+
+        let mut lib_bytes = fake_lib_bytes(arch.machine);
+        lib_bytes.extend(std::iter::repeat(0x42u8).take(4096 - lib_bytes.len()));
+
+        let file = File::create(&base_apk_path).unwrap();
+        let mut zip = ZipWriter::new(file);
+        let options = FileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .alignment(super::page_size() as u16);
+        zip.start_file(&lib_path, options).unwrap();
+        zip.write_all(&lib_bytes).unwrap();
+        zip.finish().unwrap();
+
+        let mut lib_source = super::open_base_lib(tmp_dir.path(), "libapp.so").unwrap();
+        assert!(matches!(lib_source, super::LibSource::Mapped(_)));
+
+        let mut read_back = Vec::new();
+        lib_source.read_to_end(&mut read_back).unwrap();
+        assert_eq!(read_back, lib_bytes);
+    }
+
+    #[test]
+    fn try_mmap_lib_test() {
+        // Lay out a file with a page of filler before the library, so we can
+        // drive try_mmap_lib with an explicit offset rather than relying on
+        // whatever offset the zip entry happens to land at.
+        let tmp_dir = TempDir::new("example").unwrap();
+        let file_path = tmp_dir.path().join("base.apk");
+        let page_size = super::page_size() as usize;
+
+        let mut lib_bytes = fake_lib_bytes(super::android_arch_names()[0].machine);
+        lib_bytes.extend(std::iter::repeat(0x42u8).take(page_size - lib_bytes.len()));
+
+        let mut contents = vec![0u8; page_size];
+        contents.extend_from_slice(&lib_bytes);
+        std::fs::write(&file_path, &contents).unwrap();
+
+        // The correct, page-aligned offset maps the library and passes the
+        // ELF magic check.
+        let lib_source = super::try_mmap_lib(&file_path, page_size as u64, lib_bytes.len())
+            .unwrap()
+            .unwrap();
+        let mut read_back = Vec::new();
+        match lib_source {
+            super::LibSource::Mapped(mut cursor) => {
+                cursor.read_to_end(&mut read_back).unwrap();
+            }
+            super::LibSource::Buffered(_) => panic!("expected a mapped source"),
+        }
+        assert_eq!(read_back, lib_bytes);
+
+        // A page-aligned but wrong offset (the filler page, not the library)
+        // maps bytes that don't start with the ELF magic, so it must be
+        // rejected rather than silently handed to bipatch.
+        let wrong = super::try_mmap_lib(&file_path, 0, lib_bytes.len()).unwrap();
+        assert!(wrong.is_none());
+    }
 }